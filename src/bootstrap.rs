@@ -0,0 +1,31 @@
+use std::net::{SocketAddr, ToSocketAddrs};
+
+/// Resolves bootstrap hosts given as `host:port` (e.g. `dht.transmissionbt.com:6881`)
+/// into socket addresses, expanding each host to all of its A/AAAA records.
+///
+/// Hosts that fail to resolve are skipped so one bad entry doesn't fail the
+/// whole batch.
+pub fn resolve_bootstrap(hosts: &[&str]) -> Vec<SocketAddr> {
+    hosts
+        .iter()
+        .filter_map(|host| host.to_socket_addrs().ok())
+        .flatten()
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resolve_bootstrap() {
+        let addrs = resolve_bootstrap(&["127.0.0.1:6881", "missing-port", "localhost:6882"]);
+
+        assert!(addrs.contains(&"127.0.0.1:6881".parse().unwrap()));
+        assert!(
+            addrs
+                .iter()
+                .any(|addr| addr.ip().is_loopback() && addr.port() == 6882)
+        );
+    }
+}
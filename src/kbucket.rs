@@ -1,9 +1,31 @@
-use std::time::Instant;
+use std::{
+    net::IpAddr,
+    time::{Duration, Instant},
+};
 
 use indexmap::IndexMap;
 
 use crate::node::{Node, NodeId};
 
+/// Churn counters for a single bucket.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct BucketStats {
+    /// Replacement nodes moved into the main set after a removal.
+    pub promotions: u64,
+    /// Nodes removed from the main set.
+    pub evictions: u64,
+    /// Replacement nodes dropped because the replacement list overflowed.
+    pub overflow_drops: u64,
+}
+
+/// Where a node sits within a bucket.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Membership {
+    Main,
+    Replacement,
+    Absent,
+}
+
 #[derive(Debug)]
 struct KBucket {
     rend: u128,
@@ -13,6 +35,8 @@ struct KBucket {
     last_updated: Instant,
     ksize: usize,
     max_replacement_nodes: usize,
+    max_per_subnet: Option<usize>,
+    stats: BucketStats,
 }
 
 impl KBucket {
@@ -30,9 +54,19 @@ impl KBucket {
             last_updated: Instant::now(),
             ksize,
             max_replacement_nodes: ksize * replacement_node_factor,
+            max_per_subnet: None,
+            stats: BucketStats::default(),
         }
     }
 
+    /// Caps how many main-set nodes may share a /24 (IPv4) or /48 (IPv6)
+    /// subnet. Nodes from a subnet at the cap are kept as replacements
+    /// instead, so a single subnet can't fill the bucket.
+    pub fn with_max_per_subnet(mut self, max_per_subnet: usize) -> Self {
+        self.max_per_subnet = Some(max_per_subnet);
+        self
+    }
+
     pub fn update_ts(&mut self) {
         self.last_updated = Instant::now();
     }
@@ -41,8 +75,40 @@ impl KBucket {
         self.nodes.values().cloned().collect()
     }
 
+    fn midpoint(&self) -> u128 {
+        self.rstart + (self.rend - self.rstart) / 2
+    }
+
+    /// Splits the bucket unless no sequence of splits could separate its nodes.
+    ///
+    /// Returns `None` when the range can't be halved or every node (main and
+    /// replacement) has the same truncated 128-bit position, e.g. ids that
+    /// share their top 16 bytes. Splitting such a bucket makes no progress, so
+    /// it should be kept as is with the overflow staying in replacements.
+    /// Nodes that share this split's half but differ deeper still split.
+    pub fn try_split(&self) -> Option<(KBucket, KBucket)> {
+        if self.rstart == self.rend {
+            return None;
+        }
+
+        let mut positions = self
+            .nodes
+            .values()
+            .chain(self.replacement_nodes.values())
+            .map(|node| node_id_to_u128(&node.id));
+        let first = positions.next()?;
+        let (min, max) = positions.fold((first, first), |(min, max), position| {
+            (min.min(position), max.max(position))
+        });
+        if min == max {
+            return None;
+        }
+
+        Some(self.split())
+    }
+
     pub fn split(&self) -> (KBucket, KBucket) {
-        let midpoint = (self.rstart + self.rend) / 2;
+        let midpoint = self.midpoint();
         let mut one = KBucket::new(
             self.rstart,
             midpoint,
@@ -55,6 +121,8 @@ impl KBucket {
             self.ksize,
             self.max_replacement_nodes / self.ksize,
         );
+        one.max_per_subnet = self.max_per_subnet;
+        two.max_per_subnet = self.max_per_subnet;
 
         let all_nodes = self.nodes.values().chain(self.replacement_nodes.values());
         for node in all_nodes {
@@ -73,10 +141,48 @@ impl KBucket {
         self.replacement_nodes.shift_remove(&node.id);
 
         if self.nodes.shift_remove(&node.id).is_some() {
-            if let Some((new_node_id, new_node)) = self.replacement_nodes.shift_remove_index(0) {
-                self.nodes.insert(new_node_id, new_node);
-            }
+            self.stats.evictions += 1;
+            self.promote_replacements();
+        }
+    }
+
+    /// Fills free main-set slots from the replacement list, taking the oldest
+    /// replacements whose subnet is under the cap.
+    fn promote_replacements(&mut self) {
+        while self.nodes.len() < self.ksize {
+            let promotable = self
+                .replacement_nodes
+                .values()
+                .position(|node| self.subnet_has_room(node));
+            let Some((new_node_id, new_node)) =
+                promotable.and_then(|index| self.replacement_nodes.shift_remove_index(index))
+            else {
+                break;
+            };
+            self.nodes.insert(new_node_id, new_node);
+            self.stats.promotions += 1;
+        }
+    }
+
+    /// Removes every node whose `last_seen` is more than `max_age` before `now`
+    /// and returns them. Stale replacements are dropped first so that only
+    /// fresh replacements are promoted into the slots freed in the main set.
+    pub fn sweep_expired(&mut self, max_age: Duration, now: Instant) -> Vec<Node> {
+        let is_expired = |node: &Node| node.age(now) > max_age;
+
+        let expired: Vec<Node> = self
+            .replacement_nodes
+            .values()
+            .chain(self.nodes.values())
+            .filter(|node| is_expired(node))
+            .cloned()
+            .collect();
+
+        for node in &expired {
+            self.remove_node(node);
         }
+
+        expired
     }
 
     pub fn has_in_range(&self, node: &Node) -> bool {
@@ -91,17 +197,25 @@ impl KBucket {
     /// Add a node to the bucket
     ///
     /// Returns `true` if the node was added to the main bucket,
-    /// `false` if it was added to replacement nodes or bucket is full
+    /// `false` if it was added to replacement nodes because the bucket is full
+    /// or the node's subnet is at the cap
     pub fn add_node(&mut self, node: Node) -> bool {
         let node_id = node.id;
 
         if self.nodes.contains_key(&node_id) {
-            self.nodes.shift_remove(&node_id);
-            self.nodes.insert(node_id, node);
-            return true;
+            if self.subnet_has_room(&node) {
+                self.nodes.shift_remove(&node_id);
+                self.nodes.insert(node_id, node);
+                // a new address may free room in the subnet the node left
+                self.promote_replacements();
+                return true;
+            }
+            // the node moved into a subnet that is at the cap
+            self.remove_node(&node);
         }
 
-        if self.nodes.len() < self.ksize {
+        if self.nodes.len() < self.ksize && self.subnet_has_room(&node) {
+            self.replacement_nodes.shift_remove(&node_id);
             self.nodes.insert(node_id, node);
             return true;
         }
@@ -111,12 +225,34 @@ impl KBucket {
 
         while self.replacement_nodes.len() > self.max_replacement_nodes {
             self.replacement_nodes.shift_remove_index(0);
+            self.stats.overflow_drops += 1;
         }
         false
     }
 
+    /// Whether `node` can join the main set without exceeding the per-subnet
+    /// cap, not counting an existing entry with the same id. Address-less
+    /// nodes and buckets without a cap always have room.
+    fn subnet_has_room(&self, node: &Node) -> bool {
+        let (Some(cap), Some(subnet)) = (self.max_per_subnet, node.ip.map(subnet_of)) else {
+            return true;
+        };
+
+        let same_subnet = self
+            .nodes
+            .values()
+            .filter(|other| other.id != node.id && other.ip.map(subnet_of) == Some(subnet))
+            .count();
+        same_subnet < cap
+    }
+
+    /// Length of the bit prefix shared by every node in the main set.
+    ///
+    /// A prefix is only meaningful between at least two ids, so an empty or
+    /// single-node bucket has depth 0. Otherwise a lone node would report the
+    /// full 160 bits and look like a bucket that is maximally deep.
     pub fn depth(&self) -> usize {
-        if self.nodes.is_empty() {
+        if self.nodes.len() < 2 {
             return 0;
         }
 
@@ -137,6 +273,16 @@ impl KBucket {
         self.nodes.get(node_id)
     }
 
+    pub fn membership(&self, node_id: &NodeId) -> Membership {
+        if self.nodes.contains_key(node_id) {
+            Membership::Main
+        } else if self.replacement_nodes.contains_key(node_id) {
+            Membership::Replacement
+        } else {
+            Membership::Absent
+        }
+    }
+
     pub fn len(&self) -> usize {
         self.nodes.len()
     }
@@ -164,6 +310,19 @@ impl KBucket {
     pub fn get_replacement_nodes(&self) -> Vec<Node> {
         self.replacement_nodes.values().cloned().collect()
     }
+
+    pub fn stats(&self) -> BucketStats {
+        self.stats
+    }
+}
+
+/// Masks an address down to the subnet used for diversity checks: /24 for
+/// IPv4 and /48 for IPv6.
+fn subnet_of(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V4(v4) => IpAddr::V4((u32::from(v4) & 0xffff_ff00).into()),
+        IpAddr::V6(v6) => IpAddr::V6((u128::from(v6) & !((1u128 << 80) - 1)).into()),
+    }
 }
 
 fn node_id_to_u128(node_id: &NodeId) -> u128 {
@@ -258,4 +417,205 @@ mod tests {
         assert_eq!(left.range().1, 100);
         assert_eq!(right.range().0, 101);
     }
+
+    #[test]
+    fn test_stats_counters() {
+        let mut bucket = KBucket::new(0, u128::MAX, 1, 1);
+        let node1 = Node::new(NodeId::random());
+        let node2 = Node::new(NodeId::random());
+        let node3 = Node::new(NodeId::random());
+
+        bucket.add_node(node1.clone());
+        bucket.add_node(node2.clone());
+        // node3 pushes node2 out of the single replacement slot
+        bucket.add_node(node3.clone());
+        assert_eq!(bucket.stats().overflow_drops, 1);
+
+        bucket.remove_node(&node1);
+        let stats = bucket.stats();
+        assert_eq!(stats.evictions, 1);
+        assert_eq!(stats.promotions, 1);
+        assert!(bucket.get(&node3.id).is_some());
+    }
+
+    #[test]
+    fn test_depth() {
+        let mut bucket = KBucket::new(0, u128::MAX, 20, 5);
+        assert_eq!(bucket.depth(), 0);
+
+        bucket.add_node(Node::new(NodeId::new([0u8; 20])));
+        assert_eq!(bucket.depth(), 0);
+
+        // 0b0001_0000 differs from the all-zero id at bit 3
+        let mut bytes = [0u8; 20];
+        bytes[0] = 0b0001_0000;
+        bucket.add_node(Node::new(NodeId::new(bytes)));
+        assert_eq!(bucket.depth(), 3);
+    }
+
+    #[test]
+    fn test_sweep_expired() {
+        let mut bucket = KBucket::new(0, u128::MAX, 2, 5);
+        let now = Instant::now() + Duration::from_secs(3600);
+
+        // nodes are stamped with the current time on creation, an hour before `now`
+        let stale = Node::new(NodeId::random());
+        let mut fresh = Node::new(NodeId::random());
+        fresh.last_seen = now;
+        let stale_replacement = Node::new(NodeId::random());
+        let mut fresh_replacement = Node::new(NodeId::random());
+        fresh_replacement.last_seen = now;
+
+        bucket.add_node(stale.clone());
+        bucket.add_node(fresh.clone());
+        bucket.add_node(stale_replacement.clone());
+        bucket.add_node(fresh_replacement.clone());
+
+        let swept = bucket.sweep_expired(Duration::from_secs(900), now);
+        let swept_ids: Vec<NodeId> = swept.iter().map(|node| node.id).collect();
+
+        assert_eq!(swept.len(), 2);
+        assert!(swept_ids.contains(&stale.id));
+        assert!(swept_ids.contains(&stale_replacement.id));
+        assert!(bucket.get(&fresh.id).is_some());
+        assert!(bucket.get(&fresh_replacement.id).is_some());
+        assert_eq!(bucket.replacement_count(), 0);
+    }
+
+    #[test]
+    fn test_try_split_stops_on_inseparable_nodes() {
+        let mut bucket = KBucket::new(0, u128::MAX, 2, 5);
+        for i in 0..4u8 {
+            // identical top 16 bytes, only the truncated tail differs
+            let mut bytes = [0xaau8; 20];
+            bytes[19] = i;
+            bucket.add_node(Node::new(NodeId::new(bytes)));
+        }
+        assert!(bucket.try_split().is_none());
+        assert_eq!(bucket.len() + bucket.replacement_count(), 4);
+
+        let mut bucket = KBucket::new(0, u128::MAX, 2, 5);
+        bucket.add_node(Node::new(NodeId::new([0u8; 20])));
+        bucket.add_node(Node::new(NodeId::new([0xffu8; 20])));
+        let (left, right) = bucket.try_split().unwrap();
+        assert_eq!((left.len(), right.len()), (1, 1));
+
+        // both ids fall in the upper half, but a deeper split separates them
+        let mut bucket = KBucket::new(0, u128::MAX, 2, 5);
+        let mut low = [0u8; 20];
+        low[0] = 0xf0;
+        let mut high = [0xffu8; 20];
+        high[0] = 0xf0;
+        bucket.add_node(Node::new(NodeId::new(low)));
+        bucket.add_node(Node::new(NodeId::new(high)));
+        let (left, right) = bucket.try_split().unwrap();
+        assert_eq!((left.len(), right.len()), (0, 2));
+
+        // keep halving the child holding both ids until they separate at bit 8
+        let mut bucket = right;
+        let mut splits = 1;
+        loop {
+            let (left, right) = bucket.try_split().unwrap();
+            splits += 1;
+            if left.len() == 1 {
+                assert_eq!(right.len(), 1);
+                break;
+            }
+            bucket = if left.is_empty() { right } else { left };
+        }
+        assert_eq!(splits, 9);
+    }
+
+    #[test]
+    fn test_membership() {
+        let mut bucket = KBucket::new(0, u128::MAX, 1, 5);
+        let main = Node::new(NodeId::random());
+        let replacement = Node::new(NodeId::random());
+
+        bucket.add_node(main.clone());
+        bucket.add_node(replacement.clone());
+
+        assert_eq!(bucket.membership(&main.id), Membership::Main);
+        assert_eq!(bucket.membership(&replacement.id), Membership::Replacement);
+        assert_eq!(bucket.membership(&NodeId::random()), Membership::Absent);
+    }
+
+    #[test]
+    fn test_subnet_cap() {
+        let mut bucket = KBucket::new(0, u128::MAX, 8, 5).with_max_per_subnet(2);
+        let same_subnet: Vec<Node> = (1..=5)
+            .map(|i| Node::with_address(NodeId::random(), IpAddr::from([10, 0, 0, i]), 6881))
+            .collect();
+        for node in &same_subnet {
+            bucket.add_node(node.clone());
+        }
+        let other = Node::with_address(NodeId::random(), IpAddr::from([10, 0, 1, 1]), 6881);
+
+        assert!(bucket.add_node(other.clone()));
+        assert_eq!(bucket.len(), 3);
+        assert_eq!(bucket.replacement_count(), 3);
+
+        // a freed slot is only refilled from the subnet if it drops below the cap
+        bucket.remove_node(&other);
+        assert_eq!(bucket.len(), 2);
+        bucket.remove_node(&same_subnet[0]);
+        assert_eq!(bucket.len(), 2);
+        assert_eq!(bucket.membership(&same_subnet[2].id), Membership::Main);
+    }
+
+    #[test]
+    fn test_subnet_cap_on_readd_with_new_address() {
+        let mut bucket = KBucket::new(0, u128::MAX, 8, 5).with_max_per_subnet(2);
+        for i in 1..=2 {
+            bucket.add_node(Node::with_address(
+                NodeId::random(),
+                IpAddr::from([10, 0, 0, i]),
+                6881,
+            ));
+        }
+        let id = NodeId::random();
+        assert!(bucket.add_node(Node::with_address(id, IpAddr::from([10, 0, 1, 1]), 6881)));
+
+        // refreshing with an address in the same subnet is still allowed
+        assert!(bucket.add_node(Node::with_address(id, IpAddr::from([10, 0, 1, 2]), 6881)));
+
+        // moving into the full 10.0.0.0/24 demotes the node instead
+        assert!(!bucket.add_node(Node::with_address(id, IpAddr::from([10, 0, 0, 3]), 6881)));
+        assert_eq!(bucket.membership(&id), Membership::Replacement);
+        assert_eq!(bucket.len(), 2);
+    }
+
+    #[test]
+    fn test_replacement_readded_into_main_leaves_replacements() {
+        let mut bucket = KBucket::new(0, u128::MAX, 8, 5).with_max_per_subnet(1);
+        let m = NodeId::random();
+        let x = NodeId::random();
+
+        bucket.add_node(Node::with_address(m, IpAddr::from([10, 0, 0, 1]), 6881));
+        assert!(!bucket.add_node(Node::with_address(x, IpAddr::from([10, 0, 0, 2]), 6881)));
+
+        // m leaves 10.0.0.0/24, so x now fits in the main set
+        bucket.add_node(Node::with_address(m, IpAddr::from([10, 0, 9, 1]), 6881));
+        bucket.add_node(Node::with_address(x, IpAddr::from([10, 0, 0, 2]), 6881));
+
+        assert_eq!(bucket.len(), 2);
+        assert_eq!(bucket.replacement_count(), 0);
+        assert_eq!(bucket.membership(&x), Membership::Main);
+    }
+
+    #[test]
+    fn test_refresh_out_of_capped_subnet_promotes_replacement() {
+        let mut bucket = KBucket::new(0, u128::MAX, 8, 5).with_max_per_subnet(1);
+        let m = NodeId::random();
+        let x = NodeId::random();
+
+        bucket.add_node(Node::with_address(m, IpAddr::from([10, 0, 0, 1]), 6881));
+        bucket.add_node(Node::with_address(x, IpAddr::from([10, 0, 0, 2]), 6881));
+        assert_eq!(bucket.membership(&x), Membership::Replacement);
+
+        bucket.add_node(Node::with_address(m, IpAddr::from([10, 0, 9, 1]), 6881));
+        assert_eq!(bucket.membership(&x), Membership::Main);
+        assert_eq!(bucket.replacement_count(), 0);
+        assert_eq!(bucket.stats().promotions, 1);
+    }
 }
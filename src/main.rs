@@ -1,3 +1,8 @@
+// The binary is still a placeholder that doesn't use the DHT modules yet, so
+// without this every item in them is reported as dead code.
+#![allow(dead_code)]
+
+mod bootstrap;
 mod kbucket;
 mod node;
 mod node_heap;
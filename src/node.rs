@@ -1,6 +1,7 @@
 use std::{
     fmt,
     net::{IpAddr, SocketAddr},
+    time::{Duration, Instant},
 };
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
@@ -22,8 +23,19 @@ impl NodeId {
     }
 
     pub fn random() -> Self {
-        use rand::Rng;
-        let mut rng = rand::rng();
+        Self::random_with(&mut rand::rng())
+    }
+
+    /// Generates an id from the operating system's CSPRNG. Use this where the
+    /// id must be unpredictable; `random` uses the faster thread RNG.
+    pub fn random_secure() -> Self {
+        use rand::{TryRngCore, rngs::OsRng};
+        Self::random_with(&mut OsRng.unwrap_err())
+    }
+
+    /// Like `random`, but draws from the given RNG so a seeded RNG yields a
+    /// reproducible sequence of ids.
+    pub fn random_with<R: rand::Rng + ?Sized>(rng: &mut R) -> Self {
         let mut bytes = [0u8; 20];
         rng.fill(&mut bytes);
         Self(bytes)
@@ -35,12 +47,40 @@ impl NodeId {
 
     pub fn distance(&self, other: &NodeId) -> NodeId {
         let mut result = [0u8; 20];
-        for i in 0..20 {
-            result[i] = self.0[i] ^ other.0[i];
+        for (r, (a, b)) in result.iter_mut().zip(self.0.iter().zip(other.0.iter())) {
+            *r = a ^ b;
         }
         NodeId(result)
     }
 
+    /// Returns the top `bits` bits of the id as an integer, for grouping ids
+    /// into `2^bits` prefix groups. `bits` is clamped to 64.
+    pub fn prefix_group(&self, bits: u32) -> u64 {
+        let bits = bits.min(64);
+        if bits == 0 {
+            return 0;
+        }
+
+        let mut top = [0u8; 8];
+        top.copy_from_slice(&self.0[0..8]);
+        u64::from_be_bytes(top) >> (64 - bits)
+    }
+
+    /// First 4 bytes of the id as hex, for compact log output.
+    pub fn short_hex(&self) -> String {
+        self.0[..4]
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect()
+    }
+
+    /// Number of leading bits this id shares with `other`.
+    pub fn common_prefix_len(&self, other: &NodeId) -> u32 {
+        self.distance(other).leading_zeros()
+    }
+
+    /// Leading zero bits of the raw id bytes. This is only a bucket measure
+    /// when applied to a distance; use `bucket_label` for bucket math.
     pub fn leading_zeros(&self) -> u32 {
         for (i, &byte) in self.0.iter().enumerate() {
             if byte != 0 {
@@ -51,11 +91,29 @@ impl NodeId {
     }
 }
 
+/// Bucket index of `other` relative to `local`: `159 - common_prefix_len`, so
+/// 0 holds the closest ids and 159 those differing in the first bit. Unlike
+/// `leading_zeros`, which counts bits of a single id, this is relative to the
+/// local id. `local` itself maps to bucket 0.
+pub fn bucket_label(local: &NodeId, other: &NodeId) -> usize {
+    159usize.saturating_sub(local.common_prefix_len(other) as usize)
+}
+
+impl fmt::Display for NodeId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for byte in &self.0 {
+            write!(f, "{:02x}", byte)?;
+        }
+        Ok(())
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct Node {
     pub id: NodeId,
     pub ip: Option<IpAddr>,
     pub port: Option<u16>,
+    pub last_seen: Instant,
 }
 
 impl Node {
@@ -64,23 +122,35 @@ impl Node {
             id: node_id,
             ip: None,
             port: None,
+            last_seen: Instant::now(),
         }
     }
 
+    /// IPv4-mapped IPv6 addresses (`::ffff:a.b.c.d`) are normalized to IPv4
+    /// so such nodes are treated as IPv4 contacts.
     pub fn with_address(node_id: NodeId, ip: IpAddr, port: u16) -> Self {
         Self {
             id: node_id,
-            ip: Some(ip),
+            ip: Some(ip.to_canonical()),
             port: Some(port),
+            last_seen: Instant::now(),
         }
     }
 
+    /// Builds a fully-specified node from a known id and address.
+    pub fn from_parts(id: NodeId, addr: SocketAddr) -> Self {
+        Self::with_address(id, addr.ip(), addr.port())
+    }
+
+    /// Builds a node for an address whose id isn't known yet. The id is
+    /// random and must be replaced once the real one is learned.
+    pub fn placeholder_from_addr(addr: SocketAddr) -> Self {
+        Self::from_parts(NodeId::random(), addr)
+    }
+
+    #[deprecated(note = "the id is random; use `from_parts` or `placeholder_from_addr`")]
     pub fn from_socket_addr(addr: SocketAddr) -> Self {
-        Self {
-            id: NodeId::random(),
-            ip: Some(addr.ip()),
-            port: Some(addr.port()),
-        }
+        Self::placeholder_from_addr(addr)
     }
 
     pub fn same_home_as(&self, other: &Node) -> bool {
@@ -102,7 +172,242 @@ impl Node {
         self.ip.is_some() && self.port.is_some()
     }
 
+    /// Time since the node was last seen, or zero if `now` is earlier.
+    pub fn age(&self, now: Instant) -> Duration {
+        now.saturating_duration_since(self.last_seen)
+    }
+
     pub fn as_tuple(&self) -> (NodeId, Option<IpAddr>, Option<u16>) {
         (self.id, self.ip, self.port)
     }
 }
+
+/// Formats as `<short id>@<ip>:<port>`, or `<short id>@?` without an address.
+impl fmt::Display for Node {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.socket_addr() {
+            Some(addr) => write!(f, "{}@{}", self.id.short_hex(), addr),
+            None => write!(f, "{}@?", self.id.short_hex()),
+        }
+    }
+}
+
+/// Returned when converting a node without a known address into an
+/// address-bearing type.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MissingAddress;
+
+impl fmt::Display for MissingAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("node has no address")
+    }
+}
+
+impl std::error::Error for MissingAddress {}
+
+impl TryFrom<&Node> for (NodeId, SocketAddr) {
+    type Error = MissingAddress;
+
+    fn try_from(node: &Node) -> Result<Self, Self::Error> {
+        node.socket_addr()
+            .map(|addr| (node.id, addr))
+            .ok_or(MissingAddress)
+    }
+}
+
+impl From<(NodeId, SocketAddr)> for Node {
+    fn from((id, addr): (NodeId, SocketAddr)) -> Self {
+        Node::from_parts(id, addr)
+    }
+}
+
+/// Incrementally builds a `Node` from partial information.
+#[derive(Debug, Clone, Default)]
+pub struct NodeBuilder {
+    id: Option<NodeId>,
+    ip: Option<IpAddr>,
+    port: Option<u16>,
+    last_seen: Option<Instant>,
+}
+
+impl NodeBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn id(mut self, id: NodeId) -> Self {
+        self.id = Some(id);
+        self
+    }
+
+    pub fn ip(mut self, ip: IpAddr) -> Self {
+        self.ip = Some(ip);
+        self
+    }
+
+    pub fn port(mut self, port: u16) -> Self {
+        self.port = Some(port);
+        self
+    }
+
+    pub fn last_seen(mut self, last_seen: Instant) -> Self {
+        self.last_seen = Some(last_seen);
+        self
+    }
+
+    /// Returns `None` if no id was set. `last_seen` defaults to now.
+    pub fn build(self) -> Option<Node> {
+        Some(Node {
+            id: self.id?,
+            ip: self.ip.map(|ip| ip.to_canonical()),
+            port: self.port,
+            last_seen: self.last_seen.unwrap_or_else(Instant::now),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_from_parts() {
+        let id = NodeId::random();
+        let addr: SocketAddr = "10.0.0.1:6881".parse().unwrap();
+        let node = Node::from_parts(id, addr);
+
+        assert_eq!(node.id, id);
+        assert_eq!(node.ip, Some(addr.ip()));
+        assert_eq!(node.port, Some(addr.port()));
+        assert_eq!(node.socket_addr(), Some(addr));
+    }
+
+    #[test]
+    fn test_prefix_group() {
+        let mut a = [0u8; 20];
+        a[0] = 0b1010_1100;
+        let mut b = [0xffu8; 20];
+        b[0] = 0b1010_0011;
+        let (a, b) = (NodeId::new(a), NodeId::new(b));
+
+        assert_eq!(a.prefix_group(4), 0b1010);
+        assert_eq!(a.prefix_group(4), b.prefix_group(4));
+        assert_ne!(a.prefix_group(5), b.prefix_group(5));
+        assert_eq!(a.prefix_group(0), 0);
+        assert_eq!(b.prefix_group(100), b.prefix_group(64));
+    }
+
+    #[test]
+    fn test_random_with_seed_is_deterministic() {
+        use rand::{SeedableRng, rngs::StdRng};
+
+        let ids = |seed| {
+            let mut rng = StdRng::seed_from_u64(seed);
+            (0..8)
+                .map(|_| NodeId::random_with(&mut rng))
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(ids(42), ids(42));
+        assert_ne!(ids(42), ids(43));
+    }
+
+    #[test]
+    fn test_node_builder() {
+        let id = NodeId::random();
+        let node = NodeBuilder::new().id(id).build().unwrap();
+        assert_eq!(node.id, id);
+        assert!(!node.has_address());
+
+        let ip: IpAddr = "192.168.1.10".parse().unwrap();
+        let seen = Instant::now();
+        let node = NodeBuilder::new()
+            .id(id)
+            .ip(ip)
+            .port(6881)
+            .last_seen(seen)
+            .build()
+            .unwrap();
+        assert_eq!(node.id, id);
+        assert_eq!(node.ip, Some(ip));
+        assert_eq!(node.port, Some(6881));
+        assert_eq!(node.last_seen, seen);
+
+        assert!(NodeBuilder::new().port(6881).build().is_none());
+    }
+
+    #[test]
+    fn test_socket_addr_tuple_conversions() {
+        let id = NodeId::random();
+        let addr: SocketAddr = "[2001:db8::1]:6881".parse().unwrap();
+        let node = Node::from((id, addr));
+
+        let pair = <(NodeId, SocketAddr)>::try_from(&node).unwrap();
+        assert_eq!(pair, (id, addr));
+
+        let bare = Node::new(id);
+        assert_eq!(<(NodeId, SocketAddr)>::try_from(&bare), Err(MissingAddress));
+    }
+
+    #[test]
+    fn test_random_secure_is_distinct() {
+        assert_ne!(NodeId::random_secure(), NodeId::random_secure());
+    }
+
+    #[test]
+    fn test_ipv4_mapped_address_is_normalized() {
+        let addr: SocketAddr = "[::ffff:1.2.3.4]:6881".parse().unwrap();
+        let expected: IpAddr = "1.2.3.4".parse().unwrap();
+
+        let node = Node::from_parts(NodeId::random(), addr);
+        assert_eq!(node.ip, Some(expected));
+        assert!(node.socket_addr().unwrap().is_ipv4());
+
+        let node = NodeBuilder::new()
+            .id(NodeId::random())
+            .ip(addr.ip())
+            .build()
+            .unwrap();
+        assert_eq!(node.ip, Some(expected));
+    }
+
+    #[test]
+    fn test_display() {
+        let mut bytes = [0u8; 20];
+        bytes[..4].copy_from_slice(&[0xde, 0xad, 0xbe, 0xef]);
+        let id = NodeId::new(bytes);
+
+        let node = Node::from_parts(id, "1.2.3.4:6881".parse().unwrap());
+        assert_eq!(node.to_string(), "deadbeef@1.2.3.4:6881");
+        assert_eq!(Node::new(id).to_string(), "deadbeef@?");
+        assert_eq!(id.to_string(), format!("deadbeef{}", "0".repeat(32)));
+    }
+
+    #[test]
+    fn test_bucket_label() {
+        let local = NodeId::random();
+        for _ in 0..16 {
+            let other = NodeId::random();
+            let expected = 159 - local.common_prefix_len(&other) as usize;
+            assert_eq!(bucket_label(&local, &other), expected);
+        }
+
+        let mut flipped = *local.as_bytes();
+        flipped[0] ^= 0x80;
+        assert_eq!(bucket_label(&local, &NodeId::new(flipped)), 159);
+
+        let mut flipped = *local.as_bytes();
+        flipped[19] ^= 0x01;
+        assert_eq!(bucket_label(&local, &NodeId::new(flipped)), 0);
+        assert_eq!(bucket_label(&local, &local), 0);
+    }
+
+    #[test]
+    fn test_age() {
+        let node = Node::new(NodeId::random());
+        let later = node.last_seen + Duration::from_secs(90);
+
+        assert_eq!(node.age(later), Duration::from_secs(90));
+        assert_eq!(node.age(node.last_seen), Duration::ZERO);
+    }
+}
@@ -19,7 +19,7 @@ impl HeapEntry {
 
 impl PartialEq for HeapEntry {
     fn eq(&self, other: &Self) -> bool {
-        self.distance == other.distance
+        self.distance == other.distance && self.node.id == other.node.id
     }
 }
 
@@ -31,10 +31,30 @@ impl PartialOrd for HeapEntry {
     }
 }
 
+/// Entries are ordered by distance and then by node id, so the order is total
+/// and consistent with `Eq`. XOR distance to the heap's reference node is
+/// one-to-one, so entries built by `push` never tie on distance. The id
+/// tie-break only matters for entries with hand-set distances or equal ids.
 impl Ord for HeapEntry {
     fn cmp(&self, other: &Self) -> Ordering {
         // reverse the ordering so BinaryHeap becomes a min-heap
-        other.distance.cmp(&self.distance)
+        other
+            .distance
+            .cmp(&self.distance)
+            .then_with(|| other.node.id.cmp(&self.node.id))
+    }
+}
+
+/// Returns a comparator ordering nodes by XOR distance to `target`, breaking
+/// ties by node id. This is the same order `NodeHeap` iterates in, so external
+/// node lists can be sorted consistently with `nodes.sort_by(cmp_by_distance(&target))`.
+pub fn cmp_by_distance(target: &NodeId) -> impl Fn(&Node, &Node) -> Ordering {
+    let target = *target;
+    move |a, b| {
+        target
+            .distance(&a.id)
+            .cmp(&target.distance(&b.id))
+            .then_with(|| a.id.cmp(&b.id))
     }
 }
 
@@ -56,6 +76,17 @@ impl NodeHeap {
         }
     }
 
+    /// Like `new`, but preallocates room for `capacity` candidates so large
+    /// lookups don't reallocate as the heap grows.
+    pub fn with_capacity(node: Node, max_size: usize, capacity: usize) -> Self {
+        Self {
+            node,
+            heap: BinaryHeap::with_capacity(capacity),
+            contacted: HashSet::with_capacity(capacity),
+            max_size,
+        }
+    }
+
     /// Note that while this heap retains a constant visible size (based on the iterator),
     /// its actual size may be quite a bit larger than what's exposed. Therefore,
     /// removal of nodes may not change the visible size as previously added
@@ -139,13 +170,46 @@ impl NodeHeap {
 
     pub fn iter(&self) -> impl Iterator<Item = &Node> {
         let mut entries: Vec<_> = self.heap.iter().collect();
-        entries.sort_by(|a, b| a.distance.cmp(&b.distance));
+        entries.sort_by_key(|entry| (entry.distance, entry.node.id));
         entries
             .into_iter()
             .take(self.max_size)
             .map(|entry| &entry.node)
     }
 
+    /// Entry at position `min(len, max_size) - 1` by distance, found with a
+    /// selection rather than a full sort.
+    fn farthest_entry(&self) -> Option<&HeapEntry> {
+        let visible = self.len();
+        if visible == 0 {
+            return None;
+        }
+
+        let mut entries: Vec<_> = self.heap.iter().collect();
+        let (_, farthest, _) = entries
+            .select_nth_unstable_by_key(visible - 1, |entry| (entry.distance, entry.node.id));
+        Some(*farthest)
+    }
+
+    /// Returns the farthest node within the visible window, i.e. the node at
+    /// position `min(len, max_size) - 1` when ordered by distance.
+    pub fn farthest(&self) -> Option<&Node> {
+        self.farthest_entry().map(|entry| &entry.node)
+    }
+
+    /// Whether pushing `node` would place it in the visible window: true if
+    /// the window isn't full yet or `node` is closer than its farthest entry.
+    pub fn would_accept(&self, node: &Node) -> bool {
+        if self.heap.len() < self.max_size {
+            return true;
+        }
+
+        match self.farthest_entry() {
+            Some(farthest) => self.node.distance_to(node) < farthest.distance,
+            None => false,
+        }
+    }
+
     pub fn to_vec(&self) -> Vec<Node> {
         self.iter().cloned().collect()
     }
@@ -215,4 +279,85 @@ mod tests {
         assert_eq!(heap.len(), 2);
         assert_eq!(heap.actual_size(), 3);
     }
+
+    #[test]
+    fn test_farthest() {
+        let reference_node = Node::new(NodeId::random());
+        let mut heap = NodeHeap::new(reference_node.clone(), 3);
+        assert!(heap.farthest().is_none());
+
+        for _ in 0..5 {
+            heap.push_one(Node::new(NodeId::random()));
+        }
+
+        let farthest = heap.farthest().unwrap();
+        let farthest_distance = reference_node.distance_to(farthest);
+        for node in heap.iter() {
+            assert!(reference_node.distance_to(node) <= farthest_distance);
+        }
+    }
+
+    #[test]
+    fn test_equal_distance_breaks_ties_by_id() {
+        // Synthetic shared distance: `push` can't produce two different ids
+        // at the same distance, so this is only reachable by building entries
+        // directly. The lower id pops first.
+        let distance = NodeId::from_slice(&[7u8; 20]).unwrap();
+        let low = HeapEntry::new(distance, Node::new(NodeId::new([1u8; 20])));
+        let high = HeapEntry::new(distance, Node::new(NodeId::new([2u8; 20])));
+
+        assert_ne!(low, high);
+        assert_eq!(low.cmp(&high), Ordering::Greater);
+
+        let mut heap = BinaryHeap::new();
+        heap.push(high.clone());
+        heap.push(low.clone());
+        assert_eq!(heap.pop().unwrap().node.id, low.node.id);
+        assert_eq!(heap.pop().unwrap().node.id, high.node.id);
+    }
+
+    #[test]
+    fn test_cmp_by_distance_matches_heap_order() {
+        let reference_node = Node::new(NodeId::random());
+        let mut heap = NodeHeap::new(reference_node.clone(), 20);
+
+        let mut nodes: Vec<Node> = (0..10).map(|_| Node::new(NodeId::random())).collect();
+        heap.push(nodes.clone());
+
+        nodes.sort_by(cmp_by_distance(&reference_node.id));
+        let sorted_ids: Vec<NodeId> = nodes.iter().map(|node| node.id).collect();
+        assert_eq!(sorted_ids, heap.get_ids());
+    }
+
+    #[test]
+    fn test_with_capacity() {
+        let reference_node = Node::new(NodeId::random());
+        let heap = NodeHeap::with_capacity(reference_node, 20, 512);
+
+        assert!(heap.is_empty());
+        assert!(heap.heap.capacity() >= 512);
+        assert!(heap.contacted.capacity() >= 512);
+    }
+
+    #[test]
+    fn test_would_accept() {
+        let reference_node = Node::new(NodeId::new([0u8; 20]));
+        let mut heap = NodeHeap::new(reference_node, 2);
+
+        let mut near = [0u8; 20];
+        near[19] = 1;
+        let mut mid = [0u8; 20];
+        mid[10] = 1;
+        let mut far = [0u8; 20];
+        far[0] = 1;
+        let mut farther = [0u8; 20];
+        farther[0] = 0x80;
+
+        heap.push_one(Node::new(NodeId::new(mid)));
+        assert!(heap.would_accept(&Node::new(NodeId::new(farther))));
+
+        heap.push_one(Node::new(NodeId::new(far)));
+        assert!(!heap.would_accept(&Node::new(NodeId::new(farther))));
+        assert!(heap.would_accept(&Node::new(NodeId::new(near))));
+    }
 }